@@ -6,12 +6,20 @@ use diesel::{
         methods::{ExecuteDsl, LimitDsl, LoadQuery},
         RunQueryDsl,
     },
-    r2d2::{ConnectionManager, Pool, R2D2Connection},
+    r2d2::{ConnectionManager, Pool, PooledConnection, R2D2Connection},
     result::QueryResult,
     Connection,
 };
-use std::{error::Error as StdError, fmt};
-use tokio::task;
+use diesel::migration::MigrationSource;
+use diesel_migrations::MigrationHarness;
+use std::{
+    error::Error as StdError, fmt, marker::PhantomData, ops::DerefMut, sync::Arc, time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task, time,
+};
+use tokio_stream::wrappers::ReceiverStream;
 
 pub type AsyncResult<R> = Result<R, AsyncError>;
 
@@ -22,6 +30,12 @@ pub enum AsyncError {
 
     // The query failed in some way
     Error(diesel::result::Error),
+
+    // The blocking task handed to `spawn_blocking` panicked or was cancelled
+    Join(task::JoinError),
+
+    // A migration failed while running or inspecting pending migrations
+    Migration(Box<dyn StdError + Send + Sync + 'static>),
 }
 
 pub trait OptionalExtension<T> {
@@ -43,6 +57,8 @@ impl fmt::Display for AsyncError {
         match *self {
             AsyncError::Checkout(ref err) => err.fmt(f),
             AsyncError::Error(ref err) => err.fmt(f),
+            AsyncError::Join(ref err) => err.fmt(f),
+            AsyncError::Migration(ref err) => err.fmt(f),
         }
     }
 }
@@ -52,7 +68,155 @@ impl StdError for AsyncError {
         match *self {
             AsyncError::Checkout(ref err) => Some(err),
             AsyncError::Error(ref err) => Some(err),
+            AsyncError::Join(ref err) => Some(err),
+            AsyncError::Migration(ref err) => Some(&**err),
+        }
+    }
+}
+
+/// Abstracts over the connection pool so the async wrappers are not tied to
+/// r2d2. An implementor hands out a connection that dereferences to `&mut Conn`
+/// and maps its own checkout failure onto [`AsyncError`].
+///
+/// The r2d2 `Pool<ConnectionManager<Conn>>` impl is provided out of the box;
+/// users of deadpool/mobc-style pools can implement this trait for their own
+/// pool type and reuse [`AsyncConnection`] and [`AsyncRunQueryDsl`] unchanged.
+#[async_trait]
+pub trait AsyncPool<Conn>: Clone + Send + Sync + 'static
+where
+    Conn: 'static + R2D2Connection,
+{
+    type Connection: DerefMut<Target = Conn> + Send + 'static;
+
+    async fn get_conn(&self) -> AsyncResult<Self::Connection>;
+
+    /// Check out exactly one connection, issue `BEGIN`, and return a
+    /// [`TransactionGuard`] that keeps it for the lifetime of the transaction.
+    ///
+    /// Unlike [`AsyncConnection::transaction`], the guard lets several
+    /// independent `await`s share the same connection, so multi-step flows such
+    /// as "fetch-next-pending then set-running" stay inside one transaction.
+    async fn begin_transaction_async(
+        &self,
+    ) -> AsyncResult<TransactionGuard<Conn, Self::Connection>> {
+        let mut conn = self.get_conn().await?;
+        task::block_in_place(|| conn.batch_execute("BEGIN").map_err(AsyncError::Error))?;
+        Ok(TransactionGuard::new(conn))
+    }
+}
+
+/// A single pooled connection held across several awaited operations inside one
+/// transaction.
+///
+/// The connection sits behind a [`Mutex`] so each guard method serializes access
+/// to it, and the inner closures run through [`task::block_in_place`] just like
+/// [`AsyncConnection::run`]. Dropping the guard without calling [`commit`] issues
+/// a best-effort `ROLLBACK` on a detached blocking task: its outcome is not
+/// observable, and it is skipped entirely when no Tokio runtime is entered.
+/// Call [`rollback`] explicitly when you need to observe the result.
+///
+/// [`commit`]: TransactionGuard::commit
+/// [`rollback`]: TransactionGuard::rollback
+pub struct TransactionGuard<Conn, C>
+where
+    Conn: 'static + R2D2Connection,
+    C: DerefMut<Target = Conn> + Send + 'static,
+{
+    conn: Arc<Mutex<C>>,
+    committed: bool,
+    _marker: PhantomData<fn() -> Conn>,
+}
+
+impl<Conn, C> TransactionGuard<Conn, C>
+where
+    Conn: 'static + R2D2Connection,
+    C: DerefMut<Target = Conn> + Send + 'static,
+{
+    fn new(conn: C) -> Self {
+        TransactionGuard {
+            conn: Arc::new(Mutex::new(conn)),
+            committed: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Run a closure against the transaction's connection.
+    pub async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: FnOnce(&mut Conn) -> QueryResult<R> + Send,
+    {
+        let mut guard = self.conn.lock().await;
+        task::block_in_place(move || f(&mut **guard).map_err(AsyncError::Error))
+    }
+
+    /// Commit the transaction and release the connection back to the pool.
+    pub async fn commit(mut self) -> AsyncResult<()> {
+        let res = {
+            let mut guard = self.conn.lock().await;
+            task::block_in_place(move || guard.batch_execute("COMMIT").map_err(AsyncError::Error))
+        };
+        // Only mark as finished on success; a failed COMMIT leaves the
+        // transaction possibly open, so keep `committed` false and let `Drop`
+        // roll it back before the connection is recycled.
+        if res.is_ok() {
+            self.committed = true;
+        }
+        res
+    }
+
+    /// Roll the transaction back explicitly and release the connection.
+    pub async fn rollback(mut self) -> AsyncResult<()> {
+        let res = {
+            let mut guard = self.conn.lock().await;
+            task::block_in_place(move || guard.batch_execute("ROLLBACK").map_err(AsyncError::Error))
+        };
+        // Mark as finished so `Drop` does not attempt a second rollback.
+        self.committed = true;
+        res
+    }
+}
+
+impl<Conn, C> Drop for TransactionGuard<Conn, C>
+where
+    Conn: 'static + R2D2Connection,
+    C: DerefMut<Target = Conn> + Send + 'static,
+{
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        // Best-effort rollback on a blocking thread so a dropped guard does not
+        // leave the transaction open on its pooled connection. `spawn_blocking`
+        // panics without an entered runtime, so bail out if there is none (e.g.
+        // a guard dropped outside a runtime or during shutdown) rather than
+        // turning the rollback into a panic-in-`Drop`. The task is detached, so
+        // a rollback failure is unobservable — that is the "best-effort" part.
+        if tokio::runtime::Handle::try_current().is_err() {
+            return;
         }
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || {
+            let mut conn = conn.blocking_lock();
+            let _ = conn.batch_execute("ROLLBACK");
+        });
+    }
+}
+
+#[async_trait]
+impl<Conn> AsyncPool<Conn> for Pool<ConnectionManager<Conn>>
+where
+    Conn: 'static + Connection + R2D2Connection,
+{
+    type Connection = PooledConnection<ConnectionManager<Conn>>;
+
+    #[inline]
+    async fn get_conn(&self) -> AsyncResult<Self::Connection> {
+        // r2d2's `get()` is blocking: under contention it parks the caller on a
+        // condvar for up to `connection_timeout`. Perform the checkout inside
+        // `block_in_place` so it never stalls the Tokio worker directly.
+        let self_ = self.clone();
+        task::block_in_place(move || self_.get().map_err(AsyncError::Checkout))
     }
 }
 
@@ -65,18 +229,88 @@ where
 }
 
 #[async_trait]
-impl<Conn> AsyncSimpleConnection<Conn> for Pool<ConnectionManager<Conn>>
+impl<Conn, P> AsyncSimpleConnection<Conn> for P
 where
     Conn: 'static + Connection + R2D2Connection,
+    P: AsyncPool<Conn>,
 {
     #[inline]
     async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
-        let self_ = self.clone();
+        let mut conn = self.get_conn().await?;
         let query = query.to_string();
-        task::block_in_place(move || {
-            let mut conn = self_.get().map_err(AsyncError::Checkout)?;
-            conn.batch_execute(&query).map_err(AsyncError::Error)
-        })
+        task::block_in_place(move || conn.batch_execute(&query).map_err(AsyncError::Error))
+    }
+}
+
+/// Controls how [`AsyncConnection::transaction_with_retries`] backs off and when
+/// it gives up.
+///
+/// The delay between attempts grows exponentially from `base_delay`, doubling
+/// each time and capped at `max_delay`, with full jitter applied on top.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Full-jitter exponential backoff: a uniform draw from `[0, capped]` where
+    // `capped` is `base_delay * 2^(attempt - 1)` clamped to `max_delay`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let shift = (attempt.saturating_sub(1)).min(31);
+        let capped = self.base_delay.saturating_mul(1u32 << shift).min(self.max_delay);
+        let nanos = capped.as_nanos() as u64;
+        if nanos == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(pseudo_random() % (nanos + 1))
+    }
+}
+
+// A cheap, dependency-free jitter source spanning the full u64 range. We only
+// need it to spread retries across competing transactions, not for anything
+// security sensitive. Seed from the full nanosecond timestamp (not just
+// `subsec_nanos`, which would cap the draw at ~1s and defeat larger
+// `max_delay`s) and mix it through SplitMix64 so the low-entropy clock bits
+// spread across the whole range.
+fn pseudo_random() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// Postgres and MySQL abort conflicting transactions with SQLSTATE `40001`
+// (serialization failure) and `40P01`/deadlock; the correct response is to
+// retry the whole transaction.
+fn is_retryable(err: &AsyncError) -> bool {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    match err {
+        AsyncError::Error(DieselError::DatabaseError(kind, info)) => {
+            matches!(kind, DatabaseErrorKind::SerializationFailure) || {
+                // Deadlock SQLSTATEs are not yet mapped by diesel, so fall back
+                // to inspecting the driver message.
+                let msg = info.message();
+                msg.contains("40001") || msg.contains("40P01") || msg.contains("deadlock")
+            }
+        }
+        _ => false,
     }
 }
 
@@ -94,12 +328,22 @@ where
     where
         R: Send,
         Func: FnOnce(&mut Conn) -> QueryResult<R> + Send;
+
+    async fn transaction_with_retries<R, Func>(
+        &self,
+        f: Func,
+        policy: RetryPolicy,
+    ) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: Fn(&mut Conn) -> QueryResult<R> + Send + Sync + Clone;
 }
 
 #[async_trait]
-impl<Conn> AsyncConnection<Conn> for Pool<ConnectionManager<Conn>>
+impl<Conn, P> AsyncConnection<Conn> for P
 where
     Conn: 'static + Connection + R2D2Connection,
+    P: AsyncPool<Conn>,
 {
     #[inline]
     async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
@@ -107,11 +351,8 @@ where
         R: Send,
         Func: FnOnce(&mut Conn) -> QueryResult<R> + Send,
     {
-        let self_ = self.clone();
-        task::block_in_place(move || {
-            let mut conn = self_.get().map_err(AsyncError::Checkout)?;
-            f(&mut *conn).map_err(AsyncError::Error)
-        })
+        let mut conn = self.get_conn().await?;
+        task::block_in_place(move || f(&mut *conn).map_err(AsyncError::Error))
     }
 
     #[inline]
@@ -120,12 +361,115 @@ where
         R: Send,
         Func: FnOnce(&mut Conn) -> QueryResult<R> + Send,
     {
-        let self_ = self.clone();
+        let mut conn = self.get_conn().await?;
         task::block_in_place(move || {
-            let mut conn = self_.get().map_err(AsyncError::Checkout)?;
             conn.transaction(|conn| f(&mut *conn)).map_err(AsyncError::Error)
         })
     }
+
+    async fn transaction_with_retries<R, Func>(
+        &self,
+        f: Func,
+        policy: RetryPolicy,
+    ) -> AsyncResult<R>
+    where
+        R: Send,
+        Func: Fn(&mut Conn) -> QueryResult<R> + Send + Sync + Clone,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            // Each attempt checks out a fresh connection from the pool.
+            let f = f.clone();
+            match self.transaction(move |conn| f(conn)).await {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if attempt >= policy.max_attempts || !is_retryable(&e) {
+                        return Err(e);
+                    }
+                    time::sleep(policy.backoff(attempt)).await;
+                }
+            }
+        }
+    }
+}
+
+/// An execution mode that offloads the blocking diesel work onto Tokio's
+/// dedicated blocking thread pool via [`task::spawn_blocking`] instead of
+/// [`task::block_in_place`].
+///
+/// `block_in_place` panics on a `current_thread` runtime and ties up a worker
+/// thread on the multi-thread one. `SpawnBlockingPool` works on either flavor —
+/// including the plain `#[tokio::test]` default — at the cost of requiring
+/// `'static + Send` closures so the work can move onto another thread. The
+/// pooled connection is still checked out inside the blocking section.
+///
+/// # Scope
+///
+/// Only the inherent [`run`], [`transaction`], and [`batch_execute_async`]
+/// closures offered here are `current_thread`-safe. `SpawnBlockingPool`
+/// deliberately does **not** implement [`AsyncPool`]: the generic
+/// [`AsyncRunQueryDsl`] query helpers and [`AsyncMigrationHarness`] are built on
+/// [`task::block_in_place`] and therefore require a multi-threaded runtime. On a
+/// `current_thread` runtime, drive queries through these inherent closures
+/// (e.g. `pool.run(|conn| query.load(conn)).await`) instead of the DSL
+/// extension methods.
+///
+/// [`run`]: SpawnBlockingPool::run
+/// [`transaction`]: SpawnBlockingPool::transaction
+/// [`batch_execute_async`]: SpawnBlockingPool::batch_execute_async
+#[derive(Clone)]
+pub struct SpawnBlockingPool<Conn>(Pool<ConnectionManager<Conn>>)
+where
+    Conn: 'static + Connection + R2D2Connection;
+
+impl<Conn> SpawnBlockingPool<Conn>
+where
+    Conn: 'static + Connection + R2D2Connection,
+{
+    #[inline]
+    pub fn new(pool: Pool<ConnectionManager<Conn>>) -> Self {
+        Self(pool)
+    }
+
+    pub async fn batch_execute_async(&self, query: &str) -> AsyncResult<()> {
+        let pool = self.0.clone();
+        let query = query.to_string();
+        task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(AsyncError::Checkout)?;
+            conn.batch_execute(&query).map_err(AsyncError::Error)
+        })
+        .await
+        .map_err(AsyncError::Join)?
+    }
+
+    pub async fn run<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: 'static + Send,
+        Func: 'static + FnOnce(&mut Conn) -> QueryResult<R> + Send,
+    {
+        let pool = self.0.clone();
+        task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(AsyncError::Checkout)?;
+            f(&mut *conn).map_err(AsyncError::Error)
+        })
+        .await
+        .map_err(AsyncError::Join)?
+    }
+
+    pub async fn transaction<R, Func>(&self, f: Func) -> AsyncResult<R>
+    where
+        R: 'static + Send,
+        Func: 'static + FnOnce(&mut Conn) -> QueryResult<R> + Send,
+    {
+        let pool = self.0.clone();
+        task::spawn_blocking(move || {
+            let mut conn = pool.get().map_err(AsyncError::Checkout)?;
+            conn.transaction(|conn| f(&mut *conn)).map_err(AsyncError::Error)
+        })
+        .await
+        .map_err(AsyncError::Join)?
+    }
 }
 
 #[async_trait]
@@ -142,6 +486,11 @@ where
         U: Send,
         Self: LoadQuery<'query, Conn, U>;
 
+    async fn load_stream_async<U>(self, asc: &AsyncConn) -> ReceiverStream<AsyncResult<U>>
+    where
+        U: Send + 'static,
+        Self: LoadQuery<'query, Conn, U> + 'static;
+
     async fn get_result_async<U>(self, asc: &AsyncConn) -> AsyncResult<U>
     where
         U: Send,
@@ -160,48 +509,149 @@ where
 }
 
 #[async_trait]
-impl<'query, T, Conn> AsyncRunQueryDsl<'query, Conn, Pool<ConnectionManager<Conn>>> for T
+impl<'query, T, Conn, P> AsyncRunQueryDsl<'query, Conn, P> for T
 where
     T: Send + RunQueryDsl<Conn>,
     Conn: 'static + Connection + R2D2Connection,
+    P: AsyncPool<Conn>,
 {
-    async fn execute_async(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<usize>
+    async fn execute_async(self, asc: &P) -> AsyncResult<usize>
     where
         Self: ExecuteDsl<Conn>,
     {
         asc.run(|conn| self.execute(conn)).await
     }
 
-    async fn load_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<Vec<U>>
+    async fn load_async<U>(self, asc: &P) -> AsyncResult<Vec<U>>
     where
         U: Send,
         Self: LoadQuery<'query, Conn, U>,
     {
-        asc.run(|mut conn| self.load(&mut conn)).await
+        asc.run(|conn| self.load(conn)).await
+    }
+
+    async fn load_stream_async<U>(self, asc: &P) -> ReceiverStream<AsyncResult<U>>
+    where
+        U: Send + 'static,
+        Self: LoadQuery<'query, Conn, U> + 'static,
+    {
+        // The pooled connection is synchronous, so we check one out and move it
+        // into a dedicated blocking task that runs the query's iterator and pushes
+        // each deserialized row across a bounded channel. The task holds the
+        // connection for the whole iteration; if the receiver is dropped early the
+        // send fails and the task terminates, releasing the connection.
+        //
+        // Note: the checkout itself happens here at call time via `get_conn`
+        // (a `block_in_place` for the r2d2 pool), so the up-to-`connection_timeout`
+        // r2d2 wait blocks the calling worker before streaming begins, and this
+        // method therefore requires a multi-threaded runtime.
+        let (tx, rx) = mpsc::channel::<AsyncResult<U>>(128);
+        let conn = asc.get_conn().await;
+        task::spawn_blocking(move || {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            let iter = match self.internal_load(&mut *conn) {
+                Ok(iter) => iter,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(AsyncError::Error(e)));
+                    return;
+                }
+            };
+            for row in iter {
+                let row = row.map_err(AsyncError::Error);
+                let failed = row.is_err();
+                if tx.blocking_send(row).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
     }
 
-    async fn get_result_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<U>
+    async fn get_result_async<U>(self, asc: &P) -> AsyncResult<U>
     where
         U: Send,
         Self: LoadQuery<'query, Conn, U>,
     {
-        asc.run(|mut conn| self.get_result(&mut conn)).await
+        asc.run(|conn| self.get_result(conn)).await
     }
 
-    async fn get_results_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<Vec<U>>
+    async fn get_results_async<U>(self, asc: &P) -> AsyncResult<Vec<U>>
     where
         U: Send,
         Self: LoadQuery<'query, Conn, U>,
     {
-        asc.run(|mut conn| self.get_results(&mut conn)).await
+        asc.run(|conn| self.get_results(conn)).await
     }
 
-    async fn first_async<U>(self, asc: &Pool<ConnectionManager<Conn>>) -> AsyncResult<U>
+    async fn first_async<U>(self, asc: &P) -> AsyncResult<U>
     where
         U: Send,
         Self: LimitDsl,
         Limit<Self>: LoadQuery<'query, Conn, U>,
     {
-        asc.run(|mut conn| self.first(&mut conn)).await
+        asc.run(|conn| self.first(conn)).await
+    }
+}
+
+/// Runs `diesel_migrations` against the async pool, so applications can apply
+/// startup migrations on the very pool they use for queries instead of opening a
+/// separate synchronous connection.
+#[async_trait]
+pub trait AsyncMigrationHarness<Conn>
+where
+    Conn: 'static + R2D2Connection,
+{
+    /// Apply every pending migration in `source`, returning the versions that
+    /// were run.
+    async fn run_pending_migrations_async<S>(&self, source: S) -> AsyncResult<Vec<String>>
+    where
+        S: MigrationSource<Conn::Backend> + Send + 'static;
+
+    /// Return the versions of the migrations in `source` that have not yet been
+    /// applied, without running them.
+    async fn pending_migrations_async<S>(&self, source: S) -> AsyncResult<Vec<String>>
+    where
+        S: MigrationSource<Conn::Backend> + Send + 'static;
+}
+
+#[async_trait]
+impl<Conn, P> AsyncMigrationHarness<Conn> for P
+where
+    Conn: 'static + R2D2Connection + MigrationHarness<Conn::Backend>,
+    P: AsyncPool<Conn>,
+{
+    async fn run_pending_migrations_async<S>(&self, source: S) -> AsyncResult<Vec<String>>
+    where
+        S: MigrationSource<Conn::Backend> + Send + 'static,
+    {
+        let mut conn = self.get_conn().await?;
+        task::block_in_place(move || {
+            conn.run_pending_migrations(source)
+                .map(|versions| versions.iter().map(|v| v.to_string()).collect())
+                .map_err(AsyncError::Migration)
+        })
+    }
+
+    async fn pending_migrations_async<S>(&self, source: S) -> AsyncResult<Vec<String>>
+    where
+        S: MigrationSource<Conn::Backend> + Send + 'static,
+    {
+        let mut conn = self.get_conn().await?;
+        task::block_in_place(move || {
+            conn.pending_migrations(source)
+                .map(|migrations| {
+                    migrations
+                        .iter()
+                        .map(|m| m.name().version().to_string())
+                        .collect()
+                })
+                .map_err(AsyncError::Migration)
+        })
     }
 }